@@ -81,6 +81,7 @@ impl InterpretContext for () {
 
 pub(crate) trait RelationalAlgebra {
     fn name(&self) -> &str;
+    fn bindings(&self) -> &BindingMap;
 }
 
 const NAME_RA_FROM_VALUES: &str = "Values";
@@ -179,10 +180,99 @@ impl RelationalAlgebra for RaFromValues {
     fn name(&self) -> &str {
         NAME_RA_FROM_VALUES
     }
+
+    fn bindings(&self) -> &BindingMap {
+        &self.binding
+    }
 }
 
 const NAME_INSERT: &str = "Insert";
 
+/// How a bound column is used at a join or insert site, which is what index
+/// selection keys off of: a column that drives a probe into a relation, one
+/// that's merely bound for some later operator to consume, or one that's read
+/// but never matched against anything.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ColumnUse {
+    Driving,
+    Bound,
+    Ignored,
+}
+
+/// Pick the index (if any) whose key prefix matches `driving_cols` exactly, in
+/// order. An index only helps a join/insert site if its leading columns are
+/// precisely the columns driving the probe; otherwise scanning it narrows
+/// nothing over the base EAV relation, so callers should fall back to a full
+/// scan of `table_id` instead.
+pub(crate) fn select_index_for_join<'a>(
+    indices: &'a [IndexInfo],
+    driving_cols: &[String],
+) -> Option<&'a IndexInfo> {
+    if driving_cols.is_empty() {
+        return None;
+    }
+    indices
+        .iter()
+        .find(|idx| idx.cols.len() >= driving_cols.len() && idx.cols[..driving_cols.len()] == *driving_cols)
+}
+
+#[derive(Clone, Debug)]
+struct RaInsert {
+    table: TableId,
+    source: Arc<dyn RelationalAlgebra>,
+    /// Whether some index over `table` is already fully derivable from the
+    /// columns this insert provides, so whatever executes the write (outside
+    /// this planner) knows it must also maintain that index's entries
+    /// alongside the base EAV write. This is the insert-side half of index
+    /// maintenance; the read-side half — seeking a matching index at a join
+    /// site instead of scanning the base relation — needs a `From`/join
+    /// operator, which this file doesn't have yet, so `select_index_for_join`
+    /// stays unused for that purpose until one exists.
+    has_covering_index: bool,
+}
+
+impl RaInsert {
+    fn build(
+        ctx: &impl InterpretContext,
+        prev: Option<Arc<dyn RelationalAlgebra>>,
+        mut args: Pairs,
+    ) -> Result<Self> {
+        let not_enough_args = || AlgebraParseError::NotEnoughArguments(NAME_INSERT.to_string());
+        let source = prev.ok_or_else(not_enough_args)?;
+        let table_name = args.next().ok_or_else(not_enough_args)?.as_str();
+        let table = ctx
+            .resolve_table(table_name)
+            .ok_or_else(|| AlgebraParseError::TableNotFound(table_name.to_string()))?;
+        // Make sure the table (and any covering indices over it) actually
+        // exist before we commit to inserting into them.
+        ctx.get_table_info(table)?;
+        let indices = ctx.get_table_indices(table)?;
+        let bound_cols: Vec<String> = source
+            .bindings()
+            .values()
+            .flat_map(|cols| cols.keys().cloned())
+            .collect();
+        let has_covering_index = indices
+            .iter()
+            .any(|idx| idx.cols.iter().all(|c| bound_cols.contains(c)));
+        Ok(Self {
+            table,
+            source,
+            has_covering_index,
+        })
+    }
+}
+
+impl RelationalAlgebra for RaInsert {
+    fn name(&self) -> &str {
+        NAME_INSERT
+    }
+
+    fn bindings(&self) -> &BindingMap {
+        self.source.bindings()
+    }
+}
+
 pub(crate) fn build_ra_expr(
     ctx: &impl InterpretContext,
     pair: Pair,
@@ -191,7 +281,9 @@ pub(crate) fn build_ra_expr(
     for pair in pair.into_inner() {
         let mut pairs = pair.into_inner();
         match pairs.next().unwrap().as_str() {
-            NAME_INSERT => todo!(),
+            NAME_INSERT => {
+                built = Some(Arc::new(RaInsert::build(ctx, built, pairs)?));
+            }
             NAME_RA_FROM_VALUES => {
                 built = Some(Arc::new(RaFromValues::build(ctx, built, pairs)?));
             }
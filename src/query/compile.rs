@@ -86,20 +86,137 @@ pub struct RuleApplyAtom {
 
 #[derive(Clone, Debug)]
 pub struct PredicateAtom {
-    pub(crate) left: Term<DataValue>,
-    pub(crate) right: Term<DataValue>,
+    pub(crate) expr: Expr,
+}
+
+/// Binds each listed variable to a literal value unconditionally — no lookup,
+/// no join partner, just a fact. This is what lets a rewrite pass (e.g.
+/// `magic::magic_sets_rewrite`) manufacture a seed relation for argument
+/// values pulled out of a call site, the same way a query literal becomes a
+/// `Relation::singlet` join partner in `compile_conjunction` below, but
+/// without requiring an `AttrTriple` to carry it.
+#[derive(Clone, Debug)]
+pub struct ConstAtom {
+    pub(crate) bindings: Vec<(Keyword, DataValue)>,
+}
+
+/// Built-in operators usable inside a predicate atom, e.g. `Gt("?age", "?anne_age")`
+/// or an arithmetic/boolean combination of bound variables and constants.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Op {
+    Eq,
+    Neq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Not,
 }
 
 #[derive(Clone, Debug)]
 pub(crate) enum Expr {
-    Const(Term<DataValue>),
+    Const(DataValue),
+    Binding(Keyword),
+    Apply(Op, Vec<Expr>),
+}
+
+impl Expr {
+    /// Collect every variable this expression reads, so the compiler can
+    /// check they are all already bound before wiring up the filter.
+    fn collect_vars(&self, collected: &mut BTreeSet<Keyword>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Binding(kw) => {
+                collected.insert(kw.clone());
+            }
+            Expr::Apply(_, args) => {
+                for arg in args {
+                    arg.collect_vars(collected);
+                }
+            }
+        }
+    }
+
+    /// Resolve every `Binding` to its column offset in `bindings`, so
+    /// evaluating the expression against a row is an index lookup rather than
+    /// a map lookup per row.
+    fn fill_binding_indices(&self, bindings: &[Keyword]) -> Result<BoundExpr> {
+        Ok(match self {
+            Expr::Const(v) => BoundExpr::Const(v.clone()),
+            Expr::Binding(kw) => {
+                let idx = bindings.iter().position(|b| b == kw).ok_or_else(|| {
+                    QueryCompilationError::LogicError(format!(
+                        "variable {} not found among bindings {:?} when compiling predicate",
+                        kw, bindings
+                    ))
+                })?;
+                BoundExpr::Idx(idx)
+            }
+            Expr::Apply(op, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.fill_binding_indices(bindings))
+                    .collect::<Result<Vec<_>>>()?;
+                BoundExpr::Apply(*op, args)
+            }
+        })
+    }
+}
+
+/// `Expr` with every variable resolved to a column offset, ready to evaluate
+/// against an incoming relation's row without any further name lookups.
+#[derive(Clone, Debug)]
+pub(crate) enum BoundExpr {
+    Const(DataValue),
+    Idx(usize),
+    Apply(Op, Vec<BoundExpr>),
+}
+
+impl BoundExpr {
+    pub(crate) fn eval(&self, row: &[DataValue]) -> Result<DataValue> {
+        match self {
+            BoundExpr::Const(v) => Ok(v.clone()),
+            BoundExpr::Idx(i) => Ok(row[*i].clone()),
+            BoundExpr::Apply(op, args) => {
+                let vals = args
+                    .iter()
+                    .map(|a| a.eval(row))
+                    .collect::<Result<Vec<_>>>()?;
+                apply_op(*op, &vals)
+            }
+        }
+    }
+}
+
+fn apply_op(op: Op, args: &[DataValue]) -> Result<DataValue> {
+    use Op::*;
+    Ok(match op {
+        Eq => DataValue::Bool(args[0] == args[1]),
+        Neq => DataValue::Bool(args[0] != args[1]),
+        Gt => DataValue::Bool(args[0] > args[1]),
+        Ge => DataValue::Bool(args[0] >= args[1]),
+        Lt => DataValue::Bool(args[0] < args[1]),
+        Le => DataValue::Bool(args[0] <= args[1]),
+        And => DataValue::Bool(args.iter().all(|v| v.get_bool().unwrap_or(false))),
+        Or => DataValue::Bool(args.iter().any(|v| v.get_bool().unwrap_or(false))),
+        Not => DataValue::Bool(!args[0].get_bool().unwrap_or(false)),
+        Add | Sub | Mul | Div => args[0].arith(op, &args[1])?,
+    })
 }
 
 #[derive(Clone, Debug)]
 pub enum Atom {
     AttrTriple(AttrTripleAtom),
     Rule(RuleApplyAtom),
+    NotExists(RuleApplyAtom),
     Predicate(PredicateAtom),
+    Const(ConstAtom),
 }
 
 #[derive(Clone, Debug)]
@@ -108,31 +225,116 @@ pub struct RuleSet {
     pub(crate) arity: usize,
 }
 
+/// A rule body as written by the user: an arbitrary nesting of `And`/`Or`
+/// groupings over `Atom` leaves, rather than the implicit flat conjunction a
+/// plain `Vec<Atom>` would force. `compile_rule_body` normalizes this into
+/// disjunctive normal form before compiling each conjunctive branch.
+#[derive(Clone, Debug)]
+pub(crate) enum RuleBody {
+    Leaf(Atom),
+    And(Vec<RuleBody>),
+    Or(Vec<RuleBody>),
+}
+
+impl RuleBody {
+    /// Rewrite into a disjunction of conjunctions: each inner `Vec<Atom>` is a
+    /// branch compiled independently with `compile_conjunction`, then unioned
+    /// together. Called from `compile_rule_body` for every rule, not just
+    /// ones that actually use `Or` — the common single-branch case is just a
+    /// one-element disjunction.
+    pub(crate) fn to_dnf(&self) -> Vec<Vec<Atom>> {
+        match self {
+            RuleBody::Leaf(atom) => vec![vec![atom.clone()]],
+            RuleBody::Or(children) => children.iter().flat_map(|c| c.to_dnf()).collect(),
+            RuleBody::And(children) => children.iter().map(|c| c.to_dnf()).fold(
+                vec![vec![]],
+                |acc, branches| {
+                    let mut out = Vec::with_capacity(acc.len() * branches.len());
+                    for a in &acc {
+                        for b in &branches {
+                            let mut combined = a.clone();
+                            combined.extend(b.iter().cloned());
+                            out.push(combined);
+                        }
+                    }
+                    out
+                },
+            ),
+        }
+    }
+
+    fn collect_rule_refs(&self, positive: &mut BTreeSet<Keyword>, negative: &mut BTreeSet<Keyword>) {
+        match self {
+            RuleBody::Leaf(Atom::Rule(rule)) => {
+                positive.insert(rule.name.clone());
+            }
+            RuleBody::Leaf(Atom::NotExists(rule)) => {
+                negative.insert(rule.name.clone());
+            }
+            RuleBody::Leaf(Atom::AttrTriple(_))
+            | RuleBody::Leaf(Atom::Predicate(_))
+            | RuleBody::Leaf(Atom::Const(_)) => {}
+            RuleBody::And(children) | RuleBody::Or(children) => {
+                for child in children {
+                    child.collect_rule_refs(positive, negative);
+                }
+            }
+        }
+    }
+}
+
 impl Rule {
     pub(crate) fn contained_rules(&self) -> BTreeSet<Keyword> {
-        let mut collected = BTreeSet::new();
-        for clause in &self.body {
-            if let Atom::Rule(rule) = clause {
-                collected.insert(rule.name.clone());
-            }
-            // todo: negation, disjunction, etc
+        let (positive, _) = self.contained_rules_with_polarity();
+        positive
+    }
+    /// Like `contained_rules`, but splits references into those reached
+    /// positively and those reached through negation (`NotExists`), for the
+    /// stratification dependency graph. `Aggregation` (see `chunk1-5`) is
+    /// stratified the same way a negated reference is, since both are
+    /// non-monotone and require the referenced relation to be fully
+    /// materialized in an earlier stratum.
+    pub(crate) fn contained_rules_with_polarity(&self) -> (BTreeSet<Keyword>, BTreeSet<Keyword>) {
+        let mut positive = BTreeSet::new();
+        let mut negative = BTreeSet::new();
+        self.body.collect_rule_refs(&mut positive, &mut negative);
+        if self.head.iter().any(|h| !matches!(h.aggr, Aggregation::None)) {
+            negative.extend(positive.iter().cloned());
         }
-        collected
+        (positive, negative)
     }
 }
 
 pub(crate) type DatalogProgram = BTreeMap<Keyword, RuleSet>;
 
-#[derive(Clone, Debug, Default)]
+/// A reducer applied to one head-term column, grouped by the rule's other,
+/// non-aggregated head terms. `Sum`/`Mean` additionally require every row's
+/// value in that column to be numeric; that's checked once per group when the
+/// aggregation actually runs, rather than at compile time, since the values
+/// are not known until the rule body has been evaluated.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub enum Aggregation {
     #[default]
     None,
+    Count,
+    Sum,
+    Min,
+    Max,
+    Mean,
+    CollectList,
+}
+
+impl Aggregation {
+    /// Whether this reducer requires every input to be a numeric `DataValue`.
+    pub(crate) fn requires_numeric(&self) -> bool {
+        matches!(self, Aggregation::Sum | Aggregation::Mean)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct Rule {
     pub(crate) head: Vec<BindingHeadTerm>,
-    pub(crate) body: Vec<Atom>,
+    pub(crate) body: RuleBody,
     pub(crate) vld: Validity,
 }
 
@@ -156,7 +358,68 @@ impl Debug for BindingHeadFormatter<'_> {
 }
 
 impl SessionTx {
+    /// Compile `rule`: normalize its body into disjunctive normal form,
+    /// compile each conjunctive branch independently with
+    /// `compile_conjunction`, union the results, then apply head aggregation.
+    /// This is the entry point `stratified_magic_compile` calls for every
+    /// rule, so a plain conjunctive body (the common case — `to_dnf` returns
+    /// a single branch) and a disjunctive or aggregated one are both handled
+    /// here rather than only by a second, unreachable path. Every branch must
+    /// bind exactly `ret_vars` — a branch that binds a different set (e.g.
+    /// the base case of `ancestor` omitting a variable only the recursive
+    /// case uses) is rejected the same way an unsafe unbound variable within
+    /// a single branch would be.
     pub(crate) fn compile_rule_body(
+        &mut self,
+        rule: &Rule,
+        stores: &BTreeMap<Keyword, (TempStore, usize)>,
+        ret_vars: &[Keyword],
+    ) -> Result<Relation> {
+        let branches = rule.body.to_dnf();
+        let ret_vars_set: BTreeSet<_> = ret_vars.iter().cloned().collect();
+        let mut branches = branches.into_iter();
+        let first = branches
+            .next()
+            .ok_or_else(|| QueryCompilationError::LogicError("empty rule body".to_string()))?;
+        let mut ret = self.compile_conjunction(&first, rule.vld, stores, ret_vars)?;
+        for branch in branches {
+            let compiled = self.compile_conjunction(&branch, rule.vld, stores, ret_vars)?;
+            let bindings: BTreeSet<_> = compiled.bindings().into_iter().collect();
+            if bindings != ret_vars_set {
+                return Err(QueryCompilationError::UnsafeUnboundVars(
+                    ret_vars_set
+                        .symmetric_difference(&bindings)
+                        .cloned()
+                        .collect(),
+                )
+                .into());
+            }
+            ret = ret.union(compiled);
+        }
+        if rule.head.iter().any(|h| h.aggr != Aggregation::None) {
+            // Non-aggregated head terms form the grouping key; each
+            // aggregated term folds the matching rows with its reducer. This
+            // runs here, inside compile_rule_body itself, so every evaluator
+            // that calls it (stratified_magic_compile, semi_naive::eval_rule_body)
+            // applies count/sum/min/max/mean/collect on its result, not just
+            // whichever caller happened to also re-implement aggregation. It's
+            // non-monotone (adding a row to a group can change its `Max`, for
+            // instance), which is why `contained_rules_with_polarity` already
+            // forces an aggregated rule into its own stratum, same as a
+            // negated reference.
+            for head in &rule.head {
+                if head.aggr.requires_numeric() {
+                    ret.assert_numeric_column(&head.name)?;
+                }
+            }
+            ret = ret.aggregate(&rule.head)?;
+        }
+        Ok(ret)
+    }
+
+    /// Compile one conjunctive (DNF-branch) clause list — the join/anti-join/
+    /// filter compiler that `compile_rule_body` calls once per branch.
+    fn compile_conjunction(
         &mut self,
         clauses: &[Atom],
         vld: Validity,
@@ -239,40 +502,60 @@ impl SessionTx {
                         ret = ret.join(right, join_left_keys, join_right_keys);
                     }
                     (Term::Var(e_kw), Term::Var(v_kw)) => {
+                        // In this arm `_` is a throwaway: it never joins against
+                        // anything, including another `_`, and gets projected
+                        // away below since it's never added to `ret_vars`. The
+                        // triple arms with a constant on one side and rule
+                        // applications treat `_` as an ordinary variable.
+                        let is_wildcard = |k: &Keyword| k.to_string_no_prefix() == "_";
+                        // Same variable on both sides of one triple (e.g.
+                        // `T.attr("?x", "?x")`) used to panic; materialize the
+                        // value side under a fresh column and add a
+                        // self-equality filter between the two instead.
+                        let same_var = !is_wildcard(e_kw) && !is_wildcard(v_kw) && e_kw == v_kw;
+
                         let mut join_left_keys = vec![];
                         let mut join_right_keys = vec![];
-                        if e_kw == v_kw {
-                            unimplemented!();
-                        }
-                        let e_kw = {
-                            if seen_variables.contains(&e_kw) {
-                                let ret = gen_temp_kw();
-                                join_left_keys.push(e_kw.clone());
-                                join_right_keys.push(ret.clone());
-                                ret
-                            } else {
-                                seen_variables.insert(e_kw.clone());
-                                e_kw.clone()
-                            }
+
+                        let e_col = if is_wildcard(e_kw) {
+                            gen_temp_kw()
+                        } else if seen_variables.contains(e_kw) {
+                            let fresh = gen_temp_kw();
+                            join_left_keys.push(e_kw.clone());
+                            join_right_keys.push(fresh.clone());
+                            fresh
+                        } else {
+                            seen_variables.insert(e_kw.clone());
+                            e_kw.clone()
                         };
-                        let v_kw = {
-                            if seen_variables.contains(v_kw) {
-                                let ret = gen_temp_kw();
-                                join_left_keys.push(v_kw.clone());
-                                join_right_keys.push(ret.clone());
-                                ret
-                            } else {
-                                seen_variables.insert(v_kw.clone());
-                                v_kw.clone()
-                            }
+                        let v_col = if same_var || is_wildcard(v_kw) {
+                            gen_temp_kw()
+                        } else if seen_variables.contains(v_kw) {
+                            let fresh = gen_temp_kw();
+                            join_left_keys.push(v_kw.clone());
+                            join_right_keys.push(fresh.clone());
+                            fresh
+                        } else {
+                            seen_variables.insert(v_kw.clone());
+                            v_kw.clone()
                         };
-                        let right = Relation::triple(a_triple.attr.clone(), vld, e_kw, v_kw);
+                        let right =
+                            Relation::triple(a_triple.attr.clone(), vld, e_col.clone(), v_col.clone());
                         if ret.is_unit() {
                             ret = right;
                         } else {
                             debug_assert_eq!(join_left_keys.len(), join_right_keys.len());
                             ret = ret.join(right, join_left_keys, join_right_keys);
                         }
+
+                        if same_var {
+                            let eq_expr = Expr::Apply(
+                                Op::Eq,
+                                vec![Expr::Binding(e_col), Expr::Binding(v_col)],
+                            );
+                            let bound_expr = eq_expr.fill_binding_indices(&ret.bindings())?;
+                            ret = ret.filter(bound_expr);
+                        }
                     }
                     (Term::Const(eid), Term::Const(val)) => {
                         let (left_var_1, left_var_2) = (gen_temp_kw(), gen_temp_kw());
@@ -352,8 +635,62 @@ impl SessionTx {
                     debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                     ret = ret.join(right, prev_joiner_vars, right_joiner_vars);
                 }
-                Atom::Predicate(_) => {
-                    todo!()
+                Atom::NotExists(rule_app) => {
+                    let (store, arity) = stores
+                        .get(&rule_app.name)
+                        .ok_or_else(|| QueryCompilationError::UndefinedRule(rule_app.name.clone()))?
+                        .clone();
+                    if arity != rule_app.args.len() {
+                        return Err(
+                            QueryCompilationError::ArityMismatch(rule_app.name.clone()).into()
+                        );
+                    }
+
+                    let mut join_left_keys = vec![];
+                    let mut join_right_keys = vec![];
+                    for term in &rule_app.args {
+                        let var = term.get_var().ok_or_else(|| {
+                            QueryCompilationError::LogicError(
+                                "negated atoms may only reference variables, not constants"
+                                    .to_string(),
+                            )
+                        })?;
+                        if !seen_variables.contains(var) {
+                            return Err(QueryCompilationError::UnsafeUnboundVars(BTreeSet::from([
+                                var.clone(),
+                            ]))
+                            .into());
+                        }
+                        join_left_keys.push(var.clone());
+                        join_right_keys.push(gen_temp_kw());
+                    }
+                    let right = Relation::derived(join_right_keys.clone(), store);
+                    debug_assert_eq!(join_left_keys.len(), join_right_keys.len());
+                    ret = ret.anti_join(right, join_left_keys, join_right_keys);
+                }
+                Atom::Predicate(pred) => {
+                    let mut used_vars = BTreeSet::new();
+                    pred.expr.collect_vars(&mut used_vars);
+                    let unbound: BTreeSet<_> =
+                        used_vars.difference(&seen_variables).cloned().collect();
+                    if !unbound.is_empty() {
+                        return Err(QueryCompilationError::UnsafeUnboundVars(unbound).into());
+                    }
+                    let bound_expr = pred.expr.fill_binding_indices(&ret.bindings())?;
+                    ret = ret.filter(bound_expr);
+                }
+                Atom::Const(c) => {
+                    let vars = c.bindings.iter().map(|(k, _)| k.clone()).collect_vec();
+                    let vals = c.bindings.iter().map(|(_, v)| v.clone()).collect_vec();
+                    for k in &vars {
+                        seen_variables.insert(k.clone());
+                    }
+                    let const_rel = Relation::singlet(vars, vals);
+                    ret = if ret.is_unit() {
+                        const_rel
+                    } else {
+                        ret.cartesian_join(const_rel)
+                    };
                 }
             }
         }
@@ -374,6 +711,11 @@ impl SessionTx {
         }
         let cur_ret_bindings = ret.bindings();
         if ret_vars != cur_ret_bindings {
+            // `ret_vars` may itself repeat a variable when it appears more than
+            // once in the rule head (e.g. `R.same(["?x", "?x"], ...)`); since
+            // `reorder` is just a projection, listing the same bound column
+            // twice naturally enforces that every occurrence agrees, without
+            // needing a separate equality constraint.
             ret = ret.reorder(ret_vars.to_vec());
         }
 
@@ -0,0 +1,439 @@
+//! Magic-sets rewrite: push the constants a query binds at its entry point
+//! down into recursive rules, so e.g. `R.ancestor("?a", {"name": "Anne"})`
+//! only derives `ancestor` facts reachable from Anne instead of the whole
+//! relation bottom-up.
+//!
+//! For each rule-application atom we compute an *adornment* (which argument
+//! positions are bound vs. free, given left-to-right sideways information
+//! passing through the body). Every call site with at least one bound
+//! position is rewired to a specialized, adornment-named copy of the callee
+//! guarded by a `magic_<rule>_<adornment>` seed relation, and a seed rule is
+//! generated at that call site projecting the bound argument values (from a
+//! literal directly in the call, or from a variable already bound earlier in
+//! the same conjunction) into that seed relation. `NotExists` atoms are left
+//! pointing at the unrestricted original relation, since restricting the
+//! complement of a negated lookup is not generally sound the same way.
+//!
+//! Wired into `Db::run_query`'s semi-naive path (the one entry point this
+//! crate fully owns) ahead of `semi_naive::semi_naive_evaluate`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::data::keyword::Keyword;
+use crate::data::value::DataValue;
+use crate::query::compile::{
+    Aggregation, Atom, BindingHeadTerm, ConstAtom, DatalogProgram, Rule, RuleApplyAtom, RuleBody,
+    RuleSet, Term,
+};
+
+/// `b` for a bound argument position, `f` for free, read left to right —
+/// e.g. `ancestor("?a", {"name": "Anne"})` adorns as `fb` (the first arg free,
+/// the second bound by the literal).
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) struct Adornment(pub(crate) Vec<bool>);
+
+impl Adornment {
+    fn code(&self) -> String {
+        self.0
+            .iter()
+            .map(|b| if *b { 'b' } else { 'f' })
+            .collect()
+    }
+
+    fn has_bound(&self) -> bool {
+        self.0.iter().any(|b| *b)
+    }
+}
+
+fn magic_name(rule: &Keyword, adornment: &Adornment) -> Keyword {
+    Keyword::from(&format!("magic_{}_{}", rule.to_string_no_prefix(), adornment.code()) as &str)
+}
+
+/// The specialized, adornment-restricted copy of `rule`'s definitions —
+/// distinct from `magic_name`, which is the *seed* relation feeding it.
+fn adorned_name(rule: &Keyword, adornment: &Adornment) -> Keyword {
+    Keyword::from(&format!("{}${}", rule.to_string_no_prefix(), adornment.code()) as &str)
+}
+
+/// Sideways-information-passing: walk a rule body left to right, tracking
+/// which variables are already bound (by an earlier atom, or transitively by
+/// the rule's own adornment), and adorn each rule-application atom's argument
+/// positions accordingly.
+fn adorn_body(body: &RuleBody, initially_bound: &[Keyword]) -> Vec<(Keyword, Adornment)> {
+    let mut bound: BTreeSet<Keyword> = initially_bound.iter().cloned().collect();
+    let mut adornments = vec![];
+    adorn_body_rec(body, &mut bound, &mut adornments);
+    adornments
+}
+
+fn adorn_body_rec(
+    body: &RuleBody,
+    bound: &mut BTreeSet<Keyword>,
+    adornments: &mut Vec<(Keyword, Adornment)>,
+) {
+    match body {
+        RuleBody::Leaf(Atom::NotExists(app)) => {
+            // Negated lookups read the unrestricted relation, so they need no
+            // adornment (and get no seed relation) of their own.
+            for t in &app.args {
+                if let Term::Var(v) = t {
+                    bound.insert(v.clone());
+                }
+            }
+        }
+        RuleBody::Leaf(Atom::Rule(app)) => {
+            let pattern = app
+                .args
+                .iter()
+                .map(|t| match t {
+                    Term::Const(_) => true,
+                    Term::Var(v) => bound.contains(v),
+                })
+                .collect();
+            adornments.push((app.name.clone(), Adornment(pattern)));
+            for t in &app.args {
+                if let Term::Var(v) = t {
+                    bound.insert(v.clone());
+                }
+            }
+        }
+        RuleBody::Leaf(Atom::AttrTriple(triple)) => {
+            if let Term::Var(v) = &triple.entity {
+                bound.insert(v.clone());
+            }
+            if let Term::Var(v) = &triple.value {
+                bound.insert(v.clone());
+            }
+        }
+        RuleBody::Leaf(Atom::Const(c)) => {
+            for (k, _) in &c.bindings {
+                bound.insert(k.clone());
+            }
+        }
+        RuleBody::Leaf(Atom::Predicate(_)) => {}
+        RuleBody::And(children) => {
+            for child in children {
+                adorn_body_rec(child, bound, adornments);
+            }
+        }
+        RuleBody::Or(children) => {
+            // Each disjunct is sideways-informed independently off the same
+            // incoming bound set; a variable only bound inside one disjunct
+            // doesn't carry over to atoms outside the `Or`.
+            for child in children {
+                let mut branch_bound = bound.clone();
+                adorn_body_rec(child, &mut branch_bound, adornments);
+            }
+        }
+    }
+}
+
+/// One fresh variable name per call, scoped to a single `magic_sets_rewrite`
+/// invocation — used to name the head of a seed rule when a bound position is
+/// a literal rather than an already-bound variable.
+struct FreshVars(usize);
+
+impl FreshVars {
+    fn next(&mut self) -> Keyword {
+        let kw = Keyword::from(&format!("*magic{}", self.0) as &str);
+        self.0 += 1;
+        kw
+    }
+}
+
+/// Re-walk `body`'s sideways-binding sweep (same traversal as `adorn_body_rec`,
+/// but this time rewriting as it goes): every positive `Atom::Rule` call site
+/// whose resolved adornment binds at least one argument is renamed to that
+/// adornment's specialized relation, and a seed rule producing the bound
+/// argument values at that call site — from `prefix` (the atoms already
+/// processed earlier in this same conjunction, plus this rule's own guard, if
+/// any) and a literal `Atom::Const` for any position bound by a constant
+/// directly in the call — is appended to `seeds`. `NotExists` atoms are left
+/// untouched, since they read the full unrestricted relation.
+fn rewrite_and_collect_seeds(
+    body: &RuleBody,
+    bound: &mut BTreeSet<Keyword>,
+    prefix: &mut Vec<RuleBody>,
+    fresh: &mut FreshVars,
+    seeds: &mut Vec<(Keyword, Adornment, Rule)>,
+    vld: crate::Validity,
+) -> RuleBody {
+    match body {
+        RuleBody::Leaf(Atom::Rule(app)) => {
+            let pattern: Vec<bool> = app
+                .args
+                .iter()
+                .map(|t| match t {
+                    Term::Const(_) => true,
+                    Term::Var(v) => bound.contains(v),
+                })
+                .collect();
+            let adornment = Adornment(pattern);
+            let rewritten = if adornment.has_bound() {
+                let mut seed_body_atoms = prefix.clone();
+                let mut head = vec![];
+                for (term, is_bound) in app.args.iter().zip(adornment.0.iter()) {
+                    if !*is_bound {
+                        continue;
+                    }
+                    match term {
+                        Term::Var(v) => head.push(BindingHeadTerm {
+                            name: v.clone(),
+                            aggr: Aggregation::None,
+                        }),
+                        Term::Const(c) => {
+                            let fresh_var = fresh.next();
+                            seed_body_atoms.push(RuleBody::Leaf(Atom::Const(ConstAtom {
+                                bindings: vec![(fresh_var.clone(), c.clone())],
+                            })));
+                            head.push(BindingHeadTerm {
+                                name: fresh_var,
+                                aggr: Aggregation::None,
+                            });
+                        }
+                    }
+                }
+                let seed_body = if seed_body_atoms.len() == 1 {
+                    seed_body_atoms.into_iter().next().unwrap()
+                } else {
+                    RuleBody::And(seed_body_atoms)
+                };
+                seeds.push((
+                    app.name.clone(),
+                    adornment.clone(),
+                    Rule {
+                        head,
+                        body: seed_body,
+                        vld,
+                    },
+                ));
+                RuleBody::Leaf(Atom::Rule(RuleApplyAtom {
+                    name: adorned_name(&app.name, &adornment),
+                    args: app.args.clone(),
+                }))
+            } else {
+                body.clone()
+            };
+            for t in &app.args {
+                if let Term::Var(v) = t {
+                    bound.insert(v.clone());
+                }
+            }
+            prefix.push(rewritten.clone());
+            rewritten
+        }
+        RuleBody::Leaf(Atom::NotExists(app)) => {
+            for t in &app.args {
+                if let Term::Var(v) = t {
+                    bound.insert(v.clone());
+                }
+            }
+            prefix.push(body.clone());
+            body.clone()
+        }
+        RuleBody::Leaf(Atom::AttrTriple(triple)) => {
+            if let Term::Var(v) = &triple.entity {
+                bound.insert(v.clone());
+            }
+            if let Term::Var(v) = &triple.value {
+                bound.insert(v.clone());
+            }
+            prefix.push(body.clone());
+            body.clone()
+        }
+        RuleBody::Leaf(Atom::Const(c)) => {
+            for (k, _) in &c.bindings {
+                bound.insert(k.clone());
+            }
+            prefix.push(body.clone());
+            body.clone()
+        }
+        RuleBody::Leaf(Atom::Predicate(_)) => {
+            prefix.push(body.clone());
+            body.clone()
+        }
+        RuleBody::And(children) => {
+            let rewritten: Vec<RuleBody> = children
+                .iter()
+                .map(|c| rewrite_and_collect_seeds(c, bound, prefix, fresh, seeds, vld))
+                .collect();
+            RuleBody::And(rewritten)
+        }
+        RuleBody::Or(children) => {
+            // Each disjunct keeps its own local bound-set and prefix, same as
+            // `adorn_body_rec`, so a seed generated inside one branch isn't
+            // polluted by atoms that only exist in another.
+            let rewritten: Vec<RuleBody> = children
+                .iter()
+                .map(|c| {
+                    let mut branch_bound = bound.clone();
+                    let mut branch_prefix = prefix.clone();
+                    rewrite_and_collect_seeds(
+                        c,
+                        &mut branch_bound,
+                        &mut branch_prefix,
+                        fresh,
+                        seeds,
+                        vld,
+                    )
+                })
+                .collect();
+            RuleBody::Or(rewritten)
+        }
+    }
+}
+
+/// Rewrite `prog` so derivation is restricted to facts reachable from the
+/// query's bound arguments. `query_adornment` is the adornment the query
+/// itself applies to `entry`'s head (e.g. all-bound if every argument at the
+/// call site was a constant; all-free — the common case, since a top-level
+/// query has no caller of its own — when nothing outside the program binds
+/// any of its head variables up front).
+///
+/// The returned program always keeps an unrestricted copy of every original
+/// rule under its original name (so `NotExists` lookups and anything this
+/// pass declines to specialize still see the full relation), plus one
+/// `<rule>$<adornment>` ruleset and matching `magic_<rule>_<adornment>` seed
+/// relation per adornment actually required by some call site. `entry`
+/// itself is always emitted under its own, unrenamed name, since that's the
+/// key the evaluator looks up the final result under.
+pub(crate) fn magic_sets_rewrite(
+    prog: &DatalogProgram,
+    entry: &Keyword,
+    query_adornment: Adornment,
+) -> DatalogProgram {
+    let mut adornments_by_rule: BTreeMap<Keyword, Vec<Adornment>> = BTreeMap::new();
+    adornments_by_rule
+        .entry(entry.clone())
+        .or_default()
+        .push(query_adornment);
+
+    // Fixpoint: adorning one rule can discover a new adornment needed by a
+    // rule it calls, so keep sweeping until nothing new turns up.
+    loop {
+        let mut discovered = vec![];
+        for (name, adornments) in &adornments_by_rule {
+            let Some(rule_set) = prog.get(name) else {
+                continue;
+            };
+            for adornment in adornments {
+                for rule in &rule_set.rules {
+                    let initially_bound: Vec<Keyword> = rule
+                        .head
+                        .iter()
+                        .zip(adornment.0.iter())
+                        .filter(|(_, b)| **b)
+                        .map(|(h, _)| h.name.clone())
+                        .collect();
+                    for (called_name, called_adornment) in adorn_body(&rule.body, &initially_bound)
+                    {
+                        discovered.push((called_name, called_adornment));
+                    }
+                }
+            }
+        }
+        let mut changed = false;
+        for (name, adornment) in discovered {
+            let entry = adornments_by_rule.entry(name).or_default();
+            if !entry.contains(&adornment) {
+                entry.push(adornment);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Start from an unrestricted copy of everything, then overwrite/add the
+    // specialized pieces below.
+    let mut out: DatalogProgram = prog.clone();
+    let mut rewritten: BTreeMap<Keyword, (Vec<Rule>, usize)> = BTreeMap::new();
+    let mut seeds: BTreeMap<(Keyword, Adornment), Vec<Rule>> = BTreeMap::new();
+    let mut fresh = FreshVars(0);
+
+    for (name, adornments) in &adornments_by_rule {
+        let Some(rule_set) = prog.get(name) else {
+            continue;
+        };
+        for adornment in adornments {
+            let has_bound = adornment.has_bound();
+            let out_name = if name == entry {
+                // The entry rule is always looked up by its own name, even if
+                // (atypically) some of its head variables arrived pre-bound.
+                name.clone()
+            } else if has_bound {
+                adorned_name(name, adornment)
+            } else {
+                // No restriction to apply for this adornment: keep producing
+                // the full relation under the plain name.
+                name.clone()
+            };
+            for rule in &rule_set.rules {
+                let guard = if has_bound {
+                    let guard_args: Vec<Term<DataValue>> = rule
+                        .head
+                        .iter()
+                        .zip(adornment.0.iter())
+                        .filter(|(_, b)| **b)
+                        .map(|(h, _)| Term::Var(h.name.clone()))
+                        .collect();
+                    Some(RuleBody::Leaf(Atom::Rule(RuleApplyAtom {
+                        name: magic_name(name, adornment),
+                        args: guard_args,
+                    })))
+                } else {
+                    None
+                };
+
+                let mut bound: BTreeSet<Keyword> = rule
+                    .head
+                    .iter()
+                    .zip(adornment.0.iter())
+                    .filter(|(_, b)| **b)
+                    .map(|(h, _)| h.name.clone())
+                    .collect();
+                let mut prefix: Vec<RuleBody> = guard.iter().cloned().collect();
+                let mut local_seeds = vec![];
+                let rewritten_body = rewrite_and_collect_seeds(
+                    &rule.body,
+                    &mut bound,
+                    &mut prefix,
+                    &mut fresh,
+                    &mut local_seeds,
+                    rule.vld,
+                );
+                let final_body = match &guard {
+                    Some(g) => RuleBody::And(vec![g.clone(), rewritten_body]),
+                    None => rewritten_body,
+                };
+
+                let entry = rewritten
+                    .entry(out_name.clone())
+                    .or_insert_with(|| (vec![], rule_set.arity));
+                entry.0.push(Rule {
+                    head: rule.head.clone(),
+                    body: final_body,
+                    vld: rule.vld,
+                });
+
+                for (target_name, target_adornment, seed_rule) in local_seeds {
+                    seeds
+                        .entry((target_name, target_adornment))
+                        .or_default()
+                        .push(seed_rule);
+                }
+            }
+        }
+    }
+
+    for (name, (rules, arity)) in rewritten {
+        out.insert(name, RuleSet { rules, arity });
+    }
+    for ((name, adornment), rules) in seeds {
+        let arity = adornment.0.iter().filter(|b| **b).count();
+        out.insert(magic_name(&name, &adornment), RuleSet { rules, arity });
+    }
+
+    out
+}
@@ -0,0 +1,144 @@
+//! Stratification for rulesets with negation (and, per `chunk1-5`,
+//! aggregation): a negated/aggregated reference to a rule must be fully
+//! evaluated in an earlier stratum than the rule that depends on it, and
+//! recursion through negation is rejected outright since it has no
+//! well-defined fixpoint.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+
+use crate::data::keyword::Keyword;
+use crate::query::compile::{DatalogProgram, QueryCompilationError};
+
+/// A dependency graph over rule names: `positive` edges come from ordinary
+/// rule-body references, `negative` edges come from `NotExists` (or an
+/// aggregated rule reference) and must never participate in a cycle.
+struct DepGraph {
+    positive: BTreeMap<Keyword, BTreeSet<Keyword>>,
+    negative: BTreeMap<Keyword, BTreeSet<Keyword>>,
+}
+
+impl DepGraph {
+    fn build(prog: &DatalogProgram) -> Self {
+        let mut positive = BTreeMap::new();
+        let mut negative = BTreeMap::new();
+        for (name, rule_set) in prog {
+            let mut pos_deps = BTreeSet::new();
+            let mut neg_deps = BTreeSet::new();
+            for rule in &rule_set.rules {
+                let (p, n) = rule.contained_rules_with_polarity();
+                pos_deps.extend(p);
+                neg_deps.extend(n);
+            }
+            positive.insert(name.clone(), pos_deps);
+            negative.insert(name.clone(), neg_deps);
+        }
+        Self { positive, negative }
+    }
+
+    fn all_edges(&self, node: &Keyword) -> impl Iterator<Item = &Keyword> {
+        self.positive
+            .get(node)
+            .into_iter()
+            .flatten()
+            .chain(self.negative.get(node).into_iter().flatten())
+    }
+}
+
+/// Tarjan's SCC algorithm, iterative to avoid blowing the stack on deep
+/// dependency chains generated by long rule bodies.
+fn strongly_connected_components(graph: &DepGraph) -> Vec<Vec<Keyword>> {
+    let mut index_counter = 0usize;
+    let mut stack = vec![];
+    let mut on_stack = BTreeSet::new();
+    let mut indices: BTreeMap<Keyword, usize> = BTreeMap::new();
+    let mut lowlink: BTreeMap<Keyword, usize> = BTreeMap::new();
+    let mut sccs = vec![];
+
+    let nodes: Vec<Keyword> = graph.positive.keys().cloned().collect();
+
+    for node in &nodes {
+        if indices.contains_key(node) {
+            continue;
+        }
+        // Explicit work-stack DFS: each frame is (node, next child index to visit).
+        let mut work: Vec<(Keyword, usize)> = vec![(node.clone(), 0)];
+        while let Some((cur, child_idx)) = work.pop() {
+            if child_idx == 0 {
+                let idx = index_counter;
+                index_counter += 1;
+                indices.insert(cur.clone(), idx);
+                lowlink.insert(cur.clone(), idx);
+                stack.push(cur.clone());
+                on_stack.insert(cur.clone());
+            }
+            let children: Vec<Keyword> = graph.all_edges(&cur).cloned().collect();
+            if child_idx < children.len() {
+                let child = children[child_idx].clone();
+                work.push((cur.clone(), child_idx + 1));
+                if !indices.contains_key(&child) {
+                    work.push((child, 0));
+                } else if on_stack.contains(&child) {
+                    let child_idx_val = indices[&child];
+                    let cur_low = lowlink[&cur];
+                    lowlink.insert(cur.clone(), cur_low.min(child_idx_val));
+                }
+            } else {
+                // Done with all children: propagate lowlink to parent frame, if any.
+                if let Some((parent, _)) = work.last() {
+                    let parent_low = lowlink[parent];
+                    let cur_low = lowlink[&cur];
+                    lowlink.insert(parent.clone(), parent_low.min(cur_low));
+                }
+                if lowlink[&cur] == indices[&cur] {
+                    let mut scc = vec![];
+                    loop {
+                        let w = stack.pop().unwrap();
+                        on_stack.remove(&w);
+                        let done = w == cur;
+                        scc.push(w);
+                        if done {
+                            break;
+                        }
+                    }
+                    sccs.push(scc);
+                }
+            }
+        }
+    }
+    sccs
+}
+
+/// Stratify `prog`, returning the rule names grouped into strata in
+/// evaluation order (stratum 0 first). Rejects the program if any negated (or
+/// aggregated) reference participates in a cycle, since that has no
+/// well-defined semantics under stratified negation.
+pub(crate) fn stratify(prog: &DatalogProgram) -> Result<Vec<Vec<Keyword>>> {
+    let graph = DepGraph::build(prog);
+    let sccs = strongly_connected_components(&graph);
+
+    let mut scc_of: BTreeMap<Keyword, usize> = BTreeMap::new();
+    for (i, scc) in sccs.iter().enumerate() {
+        for node in scc {
+            scc_of.insert(node.clone(), i);
+        }
+    }
+
+    for (from, negs) in &graph.negative {
+        for to in negs {
+            if scc_of.get(from) == scc_of.get(to) {
+                return Err(QueryCompilationError::LogicError(format!(
+                    "negation is not stratifiable: {} and {} recurse through a negated edge",
+                    from, to
+                ))
+                .into());
+            }
+        }
+    }
+
+    // Tarjan's algorithm emits SCCs in reverse topological order already,
+    // which is exactly the stratum evaluation order we want: a stratum's
+    // dependencies (positive or negative) are always emitted before it.
+    Ok(sccs)
+}
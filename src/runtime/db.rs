@@ -13,6 +13,7 @@ use uuid::Uuid;
 
 use cozorocks::{DbBuilder, DbIter, RawRocksDb, RocksDb};
 
+use crate::data::attr::Attribute;
 use crate::data::compare::{rusty_cmp, DB_KEY_PREFIX_LEN};
 use crate::data::encode::{
     decode_ea_key, decode_value_from_key, decode_value_from_val, encode_eav_key, StorageTag,
@@ -21,15 +22,34 @@ use crate::data::id::{AttrId, EntityId, TxId, Validity};
 use crate::data::json::JsonValue;
 use crate::data::symb::PROG_ENTRY;
 use crate::data::triple::StoreOp;
-use crate::data::tuple::{rusty_scratch_cmp, SCRATCH_DB_KEY_PREFIX_LEN};
+use crate::data::tuple::{rusty_scratch_cmp, Tuple, SCRATCH_DB_KEY_PREFIX_LEN};
 use crate::data::value::DataValue;
 use crate::parse::cozoscript::query::parse_query_to_json;
 use crate::parse::cozoscript::schema::parse_schema_to_json;
 use crate::parse::cozoscript::tx::parse_tx_to_json;
 use crate::parse::schema::AttrTxItem;
+use crate::query::magic::{magic_sets_rewrite, Adornment};
 use crate::query::pull::CurrentPath;
+use crate::runtime::merge_sort::{external_merge_sort, SortBudget};
+use crate::runtime::semi_naive::semi_naive_evaluate;
 use crate::runtime::transact::SessionTx;
 
+// `Db` stays concrete over RocksDB rather than generic over a `Storage`
+// trait: every field that would need to move behind one (`db`, `temp_db`)
+// only ever flows into `SessionTx` (`tx` from `self.db.transact()...start()`
+// and `temp_store` from `temp_db`, in `transact_write`/`transact`) or
+// `DbIter` (`total_iter`), and `SessionTx`
+// itself — the only consumer of either — is declared in
+// `runtime/transact.rs` with `tx` as a concrete RocksDB transaction type, not
+// a file this module can change. A `Storage`/`StorageTx` trait here with
+// nothing on the other end to accept `S::Tx` instead of that concrete type
+// would be unused scaffolding. What this module *can* and does do in the
+// meantime: keep every RocksDB-specific tuning decision (bloom filter,
+// prefix extractor, comparator) isolated in `configure_main_store`/
+// `configure_scratch_store` below instead of inlined in `build()`, so the
+// day `SessionTx::tx` is generalized, swapping backends here is a matter of
+// calling a different pair of functions with the same shape, not re-reading
+// this whole function to find every RocksDB-specific call buried in it.
 pub struct Db {
     db: RocksDb,
     temp_db: RawRocksDb,
@@ -51,25 +71,44 @@ impl Debug for Db {
     }
 }
 
+/// RocksDB-specific tuning for the main store: a bloom filter (point lookups
+/// dominate triple access), a prefix extractor capped to the shared key
+/// prefix length so prefix iteration stays efficient, and the crate's own
+/// key-ordering comparator so triples sort the way `crate::data::encode`
+/// expects regardless of what RocksDB's default byte order would do.
+fn configure_main_store(builder: DbBuilder<'_>) -> Result<RocksDb> {
+    Ok(builder
+        .use_bloom_filter(true, 10., true)
+        .use_capped_prefix_extractor(true, DB_KEY_PREFIX_LEN)
+        .use_custom_comparator("cozo_rusty_cmp", rusty_cmp, false)
+        .build()?)
+}
+
+/// RocksDB-specific tuning for the scratch store handed to every `SessionTx`
+/// as its `temp_store`: same shape as `configure_main_store`, but against its
+/// own throwaway path/comparator/prefix length, and with
+/// `ignore_range_deletions` set. The store is created once by `Db::build`,
+/// shared by every session from `new_session`, and destroyed on exit.
+/// External merge sort does not use it; it spills to its own files.
+fn configure_scratch_store(temp_db_location: &std::path::Path) -> Result<RawRocksDb> {
+    Ok(DbBuilder::default()
+        .path(temp_db_location.to_str().unwrap())
+        .create_if_missing(true)
+        .destroy_on_exit(true)
+        .use_bloom_filter(true, 10., true)
+        .use_capped_prefix_extractor(true, SCRATCH_DB_KEY_PREFIX_LEN)
+        .use_custom_comparator("cozo_rusty_scratch_cmp", rusty_scratch_cmp, false)
+        .build_raw(true)?
+        .ignore_range_deletions(true))
+}
+
 impl Db {
     pub fn build(builder: DbBuilder<'_>) -> Result<Self> {
-        let db = builder
-            .use_bloom_filter(true, 10., true)
-            .use_capped_prefix_extractor(true, DB_KEY_PREFIX_LEN)
-            .use_custom_comparator("cozo_rusty_cmp", rusty_cmp, false)
-            .build()?;
+        let db = configure_main_store(builder)?;
         let mut temp_db_location = temp_dir();
         temp_db_location.push(format!("{}.cozo", Uuid::new_v4()));
 
-        let scratch = DbBuilder::default()
-            .path(temp_db_location.to_str().unwrap())
-            .create_if_missing(true)
-            .destroy_on_exit(true)
-            .use_bloom_filter(true, 10., true)
-            .use_capped_prefix_extractor(true, SCRATCH_DB_KEY_PREFIX_LEN)
-            .use_custom_comparator("cozo_rusty_scratch_cmp", rusty_scratch_cmp, false)
-            .build_raw(true)?
-            .ignore_range_deletions(true);
+        let scratch = configure_scratch_store(&temp_db_location)?;
         let ret = Self {
             db,
             temp_db: scratch,
@@ -126,6 +165,30 @@ impl Db {
         };
         Ok(ret)
     }
+    /// Run `f` against a fresh write transaction with a savepoint set before
+    /// `f` runs: on `Ok` the transaction is committed under `desc`; on `Err`
+    /// it is rolled back to that savepoint and the error is returned without
+    /// committing. The transaction is created and dropped inside this call,
+    /// so a failed `f` discards everything it wrote; it does not nest inside
+    /// an enclosing transaction whose earlier writes would survive.
+    pub fn transact_write_with<T>(
+        &self,
+        desc: &str,
+        f: impl FnOnce(&mut SessionTx) -> Result<T>,
+    ) -> Result<T> {
+        let mut tx = self.transact_write()?;
+        tx.set_savepoint()?;
+        match f(&mut tx) {
+            Ok(v) => {
+                tx.commit_tx(desc, false)?;
+                Ok(v)
+            }
+            Err(e) => {
+                tx.rollback_to_savepoint()?;
+                Err(e)
+            }
+        }
+    }
     pub fn transact_write(&self) -> Result<SessionTx> {
         let last_tx_id = self.last_tx_id.fetch_add(1, Ordering::AcqRel);
         let cur_tx_id = TxId(last_tx_id + 1);
@@ -180,43 +243,221 @@ impl Db {
         self.transact_triples(&payload)
     }
     pub fn transact_triples(&self, payload: &JsonValue) -> Result<JsonValue> {
+        let returning = payload
+            .get("returning")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        // Captured once, before the write, and reused for the returning pull
+        // below instead of each calling `Validity::current()` independently:
+        // two separate "now" reads straddling the write can disagree by the
+        // time the pull runs, and a retraction is only visible to a pull at a
+        // validity no later than the one it was stamped with.
+        let vld = Validity::current();
         let mut tx = self.transact_write()?;
         let (payloads, comment) = tx.parse_tx_requests(payload)?;
-        let res: JsonValue = tx
-            .tx_triples(payloads)?
+        let committed = tx.tx_triples(payloads)?;
+        let res: JsonValue = committed
             .iter()
             .map(|(eid, size)| json!([eid.0, size]))
             .collect();
         let tx_id = tx.get_write_tx_id()?;
+        let returning_vals = if returning {
+            Some(self.pull_returned_entities(
+                &mut tx,
+                committed.iter().map(|(eid, _)| *eid),
+                vld,
+            )?)
+        } else {
+            None
+        };
         tx.commit_tx(&comment, false)?;
-        Ok(json!({
+        let mut ret = json!({
             "tx_id": tx_id,
             "results": res
-        }))
+        });
+        if let Some(returning_vals) = returning_vals {
+            ret.as_object_mut()
+                .unwrap()
+                .insert("returning".to_string(), returning_vals);
+        }
+        Ok(ret)
     }
     pub fn run_tx_attributes(&self, payload: &str) -> Result<JsonValue> {
         let payload = parse_schema_to_json(payload)?;
         self.transact_attributes(&payload)
     }
     pub fn transact_attributes(&self, payload: &JsonValue) -> Result<JsonValue> {
+        let returning = payload
+            .get("returning")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
         let (attrs, comment) = AttrTxItem::parse_request(payload)?;
+        // `tx_attrs` consumes `attrs`, and a retracted attribute is no longer
+        // visible through `attr_by_id` once committed; keep the pre-commit
+        // definitions around so a retraction can still report what it
+        // retracted instead of being dropped from `returning`.
+        let attrs_by_id: BTreeMap<AttrId, Attribute> = attrs
+            .iter()
+            .map(|item| (item.attr.id, item.attr.clone()))
+            .collect();
         let mut tx = self.transact_write()?;
-        let res: JsonValue = tx
-            .tx_attrs(attrs)?
+        let committed = tx.tx_attrs(attrs)?;
+        let res: JsonValue = committed
             .iter()
             .map(|(op, aid)| json!([aid.0, op.to_string()]))
             .collect();
         let tx_id = tx.get_write_tx_id()?;
+        let returning_vals = if returning {
+            let vals = committed
+                .iter()
+                .map(|(_, aid)| -> Result<JsonValue> {
+                    match tx.attr_by_id(*aid)? {
+                        Some(attr) => Ok(attr.to_json()),
+                        None => {
+                            let attr = attrs_by_id.get(aid).ok_or_else(|| {
+                                anyhow::anyhow!("attribute {:?} not found", aid)
+                            })?;
+                            Ok(attr.to_json())
+                        }
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Some(JsonValue::Array(vals))
+        } else {
+            None
+        };
         tx.commit_tx(&comment, false)?;
-        Ok(json!({
+        let mut ret = json!({
             "tx_id": tx_id,
             "results": res
-        }))
+        });
+        if let Some(returning_vals) = returning_vals {
+            ret.as_object_mut()
+                .unwrap()
+                .insert("returning".to_string(), returning_vals);
+        }
+        Ok(ret)
+    }
+    /// Reconstruct the full entity maps for the given entity ids through the same
+    /// pull machinery used by [`Db::pull`], for use by the `:returning` transaction mode.
+    /// `vld` must be the same validity the caller's write was stamped with
+    /// (captured before the write, not re-derived here): pulling at a later
+    /// "now" would place a just-retracted value's validity interval in the
+    /// past, silently dropping it from the reconstructed map instead of
+    /// reporting what was retracted.
+    fn pull_returned_entities(
+        &self,
+        tx: &mut SessionTx,
+        eids: impl Iterator<Item = EntityId>,
+        vld: Validity,
+    ) -> Result<JsonValue> {
+        let specs = tx.parse_pull(&json!(["*"]), 0)?;
+        let mut out = vec![];
+        for eid in eids {
+            let mut collected = Default::default();
+            let mut recursive_seen = Default::default();
+            for (idx, spec) in specs.iter().enumerate() {
+                tx.pull(
+                    eid,
+                    vld,
+                    spec,
+                    0,
+                    &specs,
+                    CurrentPath::new(idx)?,
+                    &mut collected,
+                    &mut recursive_seen,
+                )?;
+            }
+            out.push(JsonValue::Object(collected));
+        }
+        Ok(JsonValue::Array(out))
     }
     pub fn current_schema(&self) -> Result<JsonValue> {
         let mut tx = self.transact()?;
         tx.all_attrs().map_ok(|v| v.to_json()).try_collect()
     }
+    /// Retire every triple for `aid` with one native range-delete per ordering
+    /// instead of one tombstone per triple. Only the attribute-leading AEV and
+    /// VAE orderings are range-contiguous for a single attribute: EAV keys are
+    /// entity-leading (`encode_eav_key(entity, attr, value, vld)`), so a single
+    /// attribute's triples are scattered throughout the EAV range rather than
+    /// occupying a contiguous slice of it, and a range-delete there would instead
+    /// wipe every attribute for every entity in between. The attribute itself is
+    /// also retracted so it can no longer be written to, and `last_attr_id` is
+    /// bumped past `aid` so nothing allocated for the rest of this `Db`'s
+    /// lifetime can reuse it and resurrect the retired data. That bump is only
+    /// in-memory: it protects every session sharing this `Db`'s `Arc<AtomicU64>`
+    /// against reuse, but not a future process that re-derives `last_attr_id`
+    /// from storage via `load_last_ids` after a restart — doing that safely
+    /// needs `load_last_ids`/`load_last_attr_id` (in `runtime/transact.rs`,
+    /// outside this module) to also account for retired ids, which this fix
+    /// does not touch.
+    pub fn retire_attribute(&self, aid: &JsonValue) -> Result<JsonValue> {
+        let aid = AttrId::try_from(aid)?;
+        let mut tx = self.transact_write()?;
+        let attr = tx
+            .attr_by_id(aid)?
+            .ok_or_else(|| anyhow::anyhow!("attribute {:?} not found", aid))?;
+
+        tx.range_delete_aev(aid).map_err(|e| {
+            anyhow::anyhow!(
+                "backend does not support native range-delete needed to retire attribute {}: {}",
+                attr.name,
+                e
+            )
+        })?;
+        tx.range_delete_vae(aid).map_err(|e| {
+            anyhow::anyhow!(
+                "backend does not support native range-delete needed to retire attribute {}: {}",
+                attr.name,
+                e
+            )
+        })?;
+
+        tx.retract_attr(aid)?;
+        let tx_id = tx.get_write_tx_id()?;
+        tx.commit_tx(&format!("retire attribute {}", attr.name), false)?;
+        self.last_attr_id.fetch_max(aid.0, Ordering::AcqRel);
+        Ok(json!({ "tx_id": tx_id, "retired": aid.0 }))
+    }
+    /// Wipe every triple whose entity id falls in `range` (inclusive start,
+    /// exclusive end) across EAV/AEV/VAE with a single native range-delete per
+    /// ordering. Intended for schema cleanup and GDPR-style bulk erasure of a
+    /// contiguous block of entities, where per-triple tombstones would be O(n).
+    /// Like `retire_attribute`, `last_ent_id` is bumped past the truncated
+    /// range's top end so this `Db`'s live sessions can't hand an id in that
+    /// range back out for the rest of the process's lifetime; see
+    /// `retire_attribute`'s doc comment for what that bump does and doesn't
+    /// cover.
+    pub fn truncate_entities(&self, range: &JsonValue) -> Result<JsonValue> {
+        let lo = EntityId::try_from(&range["lo"])?;
+        let hi = EntityId::try_from(&range["hi"])?;
+        let mut tx = self.transact_write()?;
+
+        let eav_lower = encode_eav_key(lo, AttrId::MIN_PERM, &DataValue::Bottom, Validity::MAX);
+        let eav_upper = encode_eav_key(hi, AttrId::MIN_PERM, &DataValue::Bottom, Validity::MAX);
+        let range_delete_err = |e: anyhow::Error| {
+            anyhow::anyhow!(
+                "backend does not support native range-delete needed to truncate entities [{}, {}): {}",
+                lo.0,
+                hi.0,
+                e
+            )
+        };
+        tx.range_delete(&eav_lower, &eav_upper)
+            .map_err(range_delete_err)?;
+        tx.range_delete_aev_for_entities(lo, hi)
+            .map_err(range_delete_err)?;
+        tx.range_delete_vae_for_entities(lo, hi)
+            .map_err(range_delete_err)?;
+
+        let tx_id = tx.get_write_tx_id()?;
+        tx.commit_tx(&format!("truncate entities [{}, {})", lo.0, hi.0), false)?;
+        if hi.0 > 0 {
+            self.last_ent_id.fetch_max(hi.0 - 1, Ordering::AcqRel);
+        }
+        Ok(json!({ "tx_id": tx_id }))
+    }
     pub fn entities_at(&self, vld: &JsonValue) -> Result<JsonValue> {
         let vld = match vld {
             JsonValue::Null => Validity::current(),
@@ -305,22 +546,56 @@ impl Db {
         let (input_program, out_opts, const_rules) =
             tx.parse_query(payload, &Default::default())?;
         let entry_head = &input_program.prog.get(&PROG_ENTRY).unwrap()[0].head.clone();
-        let program = input_program
-            .to_normalized_program()?
-            .stratify()?
-            .magic_sets_rewrite();
-        debug!("{:#?}", program);
-        let (compiled, mut stores) = tx.stratified_magic_compile(&program, &const_rules)?;
-        let result = tx.stratified_magic_evaluate(
-            &compiled,
-            &mut stores,
-            if out_opts.sorters.is_empty() {
-                out_opts.num_to_take()
-            } else {
-                None
-            },
-        )?;
+        let result = if const_rules.is_empty() {
+            // No query-bound constants to inject via `stratified_magic_compile`,
+            // but the entry rule's own body may still directly embed literal
+            // constants (e.g. `R.ancestor("?a", {"name": "Anne"})`) that recurse
+            // into a rule applied with those constants passed through — push
+            // those down with `magic_sets_rewrite` before handing the program to
+            // the semi-naive driver, instead of always computing the whole
+            // unrestricted relation bottom-up. `entry_head`'s own variables
+            // never arrive pre-bound here (nothing outside the query calls it),
+            // so the query adornment is all-free.
+            let query_adornment = Adornment(vec![false; entry_head.len()]);
+            let magic_prog = magic_sets_rewrite(&input_program.prog, &PROG_ENTRY, query_adornment);
+            semi_naive_evaluate(&mut tx, &magic_prog)?
+        } else {
+            let program = input_program
+                .to_normalized_program()?
+                .stratify()?
+                .magic_sets_rewrite();
+            debug!("{:#?}", program);
+            let (compiled, mut stores) = tx.stratified_magic_compile(&program, &const_rules)?;
+            tx.stratified_magic_evaluate(
+                &compiled,
+                &mut stores,
+                if out_opts.sorters.is_empty() {
+                    out_opts.num_to_take()
+                } else {
+                    None
+                },
+            )?
+        };
         if !out_opts.sorters.is_empty() {
+            let budget = SortBudget::default();
+            let row_count_hint = result.row_count_hint();
+            if row_count_hint > budget.max_rows {
+                // The result is too large to sort in memory: spill bounded runs to
+                // disk under `temp_dir()` and k-way merge them instead of
+                // materializing the whole thing through `sort_and_collect`.
+                let sort_cols = tx.resolve_sort_cols(&out_opts.sorters, entry_head)?;
+                let sorted_rows = external_merge_sort(
+                    result.scan_all(),
+                    &sort_cols,
+                    budget,
+                    out_opts.offset,
+                    out_opts.limit,
+                )?;
+                let ret: Vec<_> = tx
+                    .run_pull_on_query_results(sorted_rows.into_iter(), out_opts)?
+                    .try_collect()?;
+                return Ok(json!(ret));
+            }
             let sorted_result = tx.sort_and_collect(result, &out_opts.sorters, entry_head)?;
             let sorted_iter = if let Some(offset) = out_opts.offset {
                 Left(sorted_result.scan_sorted().skip(offset))
@@ -337,12 +612,39 @@ impl Db {
                 .try_collect()?;
             Ok(json!(ret))
         } else {
+            // `stratified_magic_evaluate` already honors `num_to_take` while
+            // materializing `result`, but `semi_naive_evaluate` (the
+            // const-rule-free path above) runs the whole fixpoint with no
+            // row cap, so offset/limit must still be applied here or they're
+            // silently dropped for queries with no const rules.
+            let rows: Box<dyn Iterator<Item = Tuple>> = match out_opts.offset {
+                Some(offset) => Box::new(result.scan_all().skip(offset)),
+                None => Box::new(result.scan_all()),
+            };
+            let rows: Box<dyn Iterator<Item = Tuple>> = match out_opts.limit {
+                Some(limit) => Box::new(rows.take(limit)),
+                None => rows,
+            };
             let ret: Vec<_> = tx
-                .run_pull_on_query_results(result.scan_all(), out_opts)?
+                .run_pull_on_query_results(rows, out_opts)?
                 .try_collect()?;
             Ok(json!(ret))
         }
     }
+    /// Emit a structured per-stratum, per-rule plan: strata are already in
+    /// evaluation order (stratum 0 first, each later stratum only ever
+    /// joining against lower/current-stratum relations), so that ordering is
+    /// preserved as-is rather than re-derived. For each rule definition, the
+    /// bindings a compiled relation actually produces are listed in place of
+    /// a raw `{:?}` dump of the relation itself, and the arity reported is
+    /// that binding count — the rule's *realized* output width — rather than
+    /// `RuleSet::arity`, which is the declared head arity and can differ once
+    /// magic-sets rewriting or unification narrows what a specific
+    /// specialization actually emits. A true join-by-join operator trace
+    /// (which stored relation each join step reads, row estimates per step)
+    /// would need the compiled `Relation`'s internal operator tree, which
+    /// this module only sees through its `bindings()`/`Debug` surface — it
+    /// isn't exposed as a walkable structure here.
     pub fn explain_query(&self, payload: &JsonValue) -> Result<JsonValue> {
         let mut tx = self.transact()?;
         let (input_program, _out_opts, const_rules) =
@@ -350,8 +652,46 @@ impl Db {
         let normalized_program = input_program.to_normalized_program()?;
         let stratified_program = normalized_program.stratify()?;
         let magic_program = stratified_program.magic_sets_rewrite();
-        let (_compiled_strata, _) = tx.stratified_magic_compile(&magic_program, &const_rules)?;
+        let (compiled_strata, _) = tx.stratified_magic_compile(&magic_program, &const_rules)?;
+
+        let strata: Vec<JsonValue> = compiled_strata
+            .iter()
+            .enumerate()
+            .map(|(stratum_idx, stratum)| {
+                let rules: Vec<JsonValue> = stratum
+                    .iter()
+                    .map(|(rule_name, compiled_rule_set)| {
+                        let is_magic_seed = rule_name.to_string_no_prefix().starts_with("magic_");
+                        let rules_json: Vec<JsonValue> = compiled_rule_set
+                            .iter()
+                            .map(|compiled_rule| {
+                                let bindings: Vec<_> = compiled_rule
+                                    .relation
+                                    .bindings()
+                                    .iter()
+                                    .map(|k| k.to_string_no_prefix())
+                                    .collect();
+                                json!({
+                                    "bindings": bindings,
+                                    "estimated_arity": compiled_rule.relation.bindings().len(),
+                                    "is_recursive": compiled_rule.is_recursive,
+                                })
+                            })
+                            .collect();
+                        json!({
+                            "name": rule_name.to_string_no_prefix(),
+                            "is_magic_seed": is_magic_seed,
+                            "definitions": rules_json,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "stratum": stratum_idx,
+                    "rules": rules,
+                })
+            })
+            .collect();
 
-        todo!()
+        Ok(json!({ "strata": strata }))
     }
 }
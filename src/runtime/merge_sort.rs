@@ -0,0 +1,256 @@
+//! External (disk-spilling) merge sort for ordered query results that are too
+//! large to sort in memory. Activated from `Db::run_query` once the result's
+//! row count exceeds the row budget; results with fewer rows keep using
+//! `SessionTx::sort_and_collect` unchanged, whatever their byte size.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::env::temp_dir;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::algebra::op::SortDir;
+use crate::data::tuple::Tuple;
+
+/// When a result has more rows than this, external merge sort kicks in instead
+/// of sorting the whole result set in memory. Once it runs, each in-memory run
+/// is spilled to disk when it reaches this many rows or
+/// `DEFAULT_RUN_BYTE_BUDGET` bytes; the byte budget alone never activates it.
+pub(crate) const DEFAULT_ROW_BUDGET: usize = 100_000;
+pub(crate) const DEFAULT_RUN_BYTE_BUDGET: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SortBudget {
+    pub(crate) max_rows: usize,
+    pub(crate) max_run_bytes: usize,
+}
+
+impl Default for SortBudget {
+    fn default() -> Self {
+        Self {
+            max_rows: DEFAULT_ROW_BUDGET,
+            max_run_bytes: DEFAULT_RUN_BYTE_BUDGET,
+        }
+    }
+}
+
+/// One sorted run spilled to a temp file as length-prefixed, bincode-encoded tuples.
+struct Run {
+    reader: BufReader<File>,
+    path: std::path::PathBuf,
+}
+
+impl Run {
+    fn create(rows: &mut Vec<Tuple>, sort_key: &dyn Fn(&Tuple) -> Vec<u8>) -> Result<Self> {
+        rows.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+
+        let mut path = temp_dir();
+        path.push(format!("{}.cozo-run", Uuid::new_v4()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for row in rows.drain(..) {
+            let encoded = bincode::serialize(&row)?;
+            writer.write_all(&(encoded.len() as u64).to_le_bytes())?;
+            writer.write_all(&encoded)?;
+        }
+        writer.flush()?;
+
+        Ok(Self {
+            reader: BufReader::new(File::open(&path)?),
+            path,
+        })
+    }
+
+    fn next_row(&mut self) -> Result<Option<Tuple>> {
+        let mut len_buf = [0u8; 8];
+        match self.reader.read_exact(&mut len_buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u64::from_le_bytes(len_buf) as usize;
+        let mut buf = vec![0u8; len];
+        self.reader.read_exact(&mut buf)?;
+        Ok(Some(bincode::deserialize(&buf)?))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// An entry sitting in the merge heap: the encoded sort key, the row itself,
+/// and the run it came from (so we know where to refill from), plus the run
+/// index so that rows with equal keys merge in stable, first-run-wins order.
+struct HeapEntry {
+    key: Vec<u8>,
+    run_idx: usize,
+    row: Tuple,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.run_idx == other.run_idx
+    }
+}
+impl Eq for HeapEntry {}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap; reverse so the smallest key pops first,
+        // breaking ties by run index to keep the merge stable.
+        other
+            .key
+            .cmp(&self.key)
+            .then_with(|| other.run_idx.cmp(&self.run_idx))
+    }
+}
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Escape every `0x00` byte in `bytes` as `0x00 0xFF` and append a `0x00 0x00`
+/// terminator. This keeps plain lexicographic order over the escaped bytes
+/// equal to lexicographic order over the original bytes (a `0x00` sorts
+/// before everything, including its own escape byte), while also making the
+/// encoding self-terminating: no escaped-and-terminated value is ever a
+/// prefix of another one, since a real value can only ever be followed by
+/// `0x00` as the start of its own terminator.
+///
+/// That prefix-freedom is what `encode_sort_key` below needs: plain
+/// lexicographic comparison already mishandles concatenating variable-length
+/// columns (`("A", "Z") < ("AB", "A")` would wrongly sort after `"A".."B"` is
+/// spliced together with no boundary), and per-byte negation for `DESC`
+/// makes it worse — flipping `"A"` and `"AB"` leaves `~"A"` a prefix of
+/// `~"A"~"B"`, so descending order would still rank `"A"` ahead of `"AB"`
+/// instead of the other way around. Terminating each column closes both
+/// holes.
+fn escape_and_terminate(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + 2);
+    for &b in bytes {
+        out.push(b);
+        if b == 0x00 {
+            out.push(0xFF);
+        }
+    }
+    out.push(0x00);
+    out.push(0x00);
+    out
+}
+
+/// Encode a tuple's sort columns into a byte string such that comparing the
+/// encodings orders rows the way `sorters` demands (every column is escaped
+/// and terminated by `escape_and_terminate`; descending columns then get
+/// bit-flipped so lexicographic order reverses).
+fn encode_sort_key(row: &Tuple, sorters: &[(usize, SortDir)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (col, dir) in sorters {
+        let mut encoded = escape_and_terminate(&row.column_sort_key(*col));
+        if *dir == SortDir::Dsc {
+            for b in encoded.iter_mut() {
+                *b = !*b;
+            }
+        }
+        out.extend(encoded);
+    }
+    out
+}
+
+/// Consume `rows` in bounded runs, sort each run in memory, spill it to a temp
+/// file, then k-way merge the runs with a binary min-heap, applying `offset`
+/// and `limit` during the merge so that, besides the rows collected for the
+/// returned `Vec`, only one buffered row per run sits in the heap.
+pub(crate) fn external_merge_sort(
+    rows: impl Iterator<Item = Tuple>,
+    sorters: &[(usize, SortDir)],
+    budget: SortBudget,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Vec<Tuple>> {
+    let key_fn = |row: &Tuple| encode_sort_key(row, sorters);
+
+    let mut runs: Vec<Run> = Vec::new();
+    let mut buf: Vec<Tuple> = Vec::new();
+    let mut buf_bytes = 0usize;
+
+    for row in rows {
+        buf_bytes += row.approx_byte_size();
+        buf.push(row);
+        if buf.len() >= budget.max_rows || buf_bytes >= budget.max_run_bytes {
+            runs.push(Run::create(&mut buf, &key_fn)?);
+            buf_bytes = 0;
+        }
+    }
+
+    if runs.is_empty() {
+        // Everything fit in the single in-memory run: no need to touch disk at all.
+        buf.sort_by(|a, b| key_fn(a).cmp(&key_fn(b)));
+        let iter = buf.into_iter();
+        return Ok(apply_offset_limit(iter, offset, limit));
+    }
+
+    if !buf.is_empty() {
+        runs.push(Run::create(&mut buf, &key_fn)?);
+    }
+
+    if limit == Some(0) {
+        return Ok(Vec::new());
+    }
+
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (idx, run) in runs.iter_mut().enumerate() {
+        if let Some(row) = run.next_row()? {
+            heap.push(HeapEntry {
+                key: key_fn(&row),
+                run_idx: idx,
+                row,
+            });
+        }
+    }
+
+    let mut to_skip = offset.unwrap_or(0);
+    let mut remaining = limit;
+    let mut out = Vec::new();
+    while let Some(HeapEntry { run_idx, row, .. }) = heap.pop() {
+        if let Some(next) = runs[run_idx].next_row()? {
+            heap.push(HeapEntry {
+                key: key_fn(&next),
+                run_idx,
+                row: next,
+            });
+        }
+        if to_skip > 0 {
+            to_skip -= 1;
+            continue;
+        }
+        out.push(row);
+        if let Some(r) = remaining.as_mut() {
+            *r -= 1;
+            if *r == 0 {
+                break;
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn apply_offset_limit(
+    iter: impl Iterator<Item = Tuple>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Vec<Tuple> {
+    let iter: Box<dyn Iterator<Item = Tuple>> = match offset {
+        Some(o) => Box::new(iter.skip(o)),
+        None => Box::new(iter),
+    };
+    match limit {
+        Some(l) => iter.take(l).collect(),
+        None => iter.collect(),
+    }
+}
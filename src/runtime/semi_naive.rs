@@ -0,0 +1,254 @@
+//! Semi-naive fixpoint evaluation for recursive rulesets (e.g. the `ancestor`
+//! example). `compile_rule_body` only ever produces a single `Relation` for
+//! one rule definition; this module is the driver that runs a whole stratum
+//! of (possibly mutually recursive) rules to a fixpoint by repeatedly
+//! substituting only the newly-derived delta into each recursive rule body,
+//! instead of recomputing the full join every round.
+//!
+//! Evaluation itself goes through the same `SessionTx::compile_rule_body`
+//! every other entry point uses, rather than a bespoke evaluator: a rule
+//! (optionally with one body occurrence renamed to point at a delta-only
+//! store) is compiled into a `Relation`, and `Relation::iter` — the
+//! execution primitive a compiled plan is always run through — turns it
+//! into concrete rows.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::data::keyword::Keyword;
+use crate::data::symb::PROG_ENTRY;
+use crate::data::tuple::Tuple;
+use crate::query::compile::{Atom, DatalogProgram, Rule, RuleBody};
+use crate::query::stratify::stratify;
+use crate::runtime::temp_store::TempStore;
+use crate::runtime::transact::SessionTx;
+
+/// Count how many times `target` is positively referenced (`Atom::Rule`, not
+/// `NotExists`) inside `body`. A rule body can reference the same recursive
+/// relation more than once (e.g. `path(x, z) :- path(x, y), path(y, z)`), and
+/// each such occurrence needs the delta substituted into it independently —
+/// the delta at occurrence 1 combined with the full store at occurrence 2,
+/// then the reverse, not the same substitution applied to every occurrence at
+/// once. Only `Atom::Rule` is counted (matching what `rename_occurrence`
+/// below renames): `target` can't also appear as `NotExists(target)` in the
+/// same stratum, since `stratify` rejects negative self-reference within an
+/// SCC before evaluation ever reaches here.
+fn count_rule_occurrences(body: &RuleBody, target: &Keyword) -> usize {
+    match body {
+        RuleBody::Leaf(Atom::Rule(app)) => usize::from(&app.name == target),
+        RuleBody::Leaf(_) => 0,
+        RuleBody::And(children) | RuleBody::Or(children) => children
+            .iter()
+            .map(|child| count_rule_occurrences(child, target))
+            .sum(),
+    }
+}
+
+/// Rewrite `body` so its `occurrence_idx`-th (0-based) positive reference to
+/// `target` is renamed to `replacement`; every other atom, including other
+/// occurrences of `target`, is left alone. Paired with a `stores` entry for
+/// `replacement` holding only the delta rows, this lets `compile_rule_body`
+/// substitute the delta into exactly one occurrence instead of all of them.
+fn rename_occurrence(
+    body: &RuleBody,
+    target: &Keyword,
+    occurrence_idx: usize,
+    replacement: &Keyword,
+    seen: &mut usize,
+) -> RuleBody {
+    match body {
+        RuleBody::Leaf(Atom::Rule(app)) if &app.name == target => {
+            let is_this_occurrence = *seen == occurrence_idx;
+            *seen += 1;
+            if is_this_occurrence {
+                let mut renamed = app.clone();
+                renamed.name = replacement.clone();
+                RuleBody::Leaf(Atom::Rule(renamed))
+            } else {
+                body.clone()
+            }
+        }
+        RuleBody::Leaf(_) => body.clone(),
+        RuleBody::And(children) => RuleBody::And(
+            children
+                .iter()
+                .map(|c| rename_occurrence(c, target, occurrence_idx, replacement, seen))
+                .collect(),
+        ),
+        RuleBody::Or(children) => RuleBody::Or(
+            children
+                .iter()
+                .map(|c| rename_occurrence(c, target, occurrence_idx, replacement, seen))
+                .collect(),
+        ),
+    }
+}
+
+/// Pair every store in `stores` with the arity `compile_rule_body` needs,
+/// taken from the program's own `RuleSet::arity` rather than from `TempStore`
+/// itself.
+fn keyed_stores(
+    prog: &DatalogProgram,
+    stores: &BTreeMap<Keyword, TempStore>,
+) -> BTreeMap<Keyword, (TempStore, usize)> {
+    stores
+        .iter()
+        .map(|(name, store)| {
+            let arity = prog.get(name).map(|rs| rs.arity).unwrap_or(0);
+            (name.clone(), (store.clone(), arity))
+        })
+        .collect()
+}
+
+/// Compile `rule` fully against `stores` and run it to concrete rows. Used
+/// for every non-recursive rule, and for a stratum's first round.
+fn eval_rule_body(
+    tx: &mut SessionTx,
+    prog: &DatalogProgram,
+    rule: &Rule,
+    stores: &BTreeMap<Keyword, TempStore>,
+) -> Result<Vec<Tuple>> {
+    let ret_vars: Vec<Keyword> = rule.head.iter().map(|h| h.name.clone()).collect();
+    let keyed = keyed_stores(prog, stores);
+    let relation = tx.compile_rule_body(rule, &keyed, &ret_vars)?;
+    relation.iter(tx)
+}
+
+/// Like `eval_rule_body`, but the `occurrence_idx`-th reference to
+/// `delta_source` inside `rule`'s body is compiled against `delta` alone
+/// instead of `delta_source`'s full store — the substitution that keeps
+/// semi-naive evaluation from recomputing the whole join every round.
+fn eval_rule_body_with_delta(
+    tx: &mut SessionTx,
+    prog: &DatalogProgram,
+    rule: &Rule,
+    stores: &BTreeMap<Keyword, TempStore>,
+    delta_source: &Keyword,
+    occurrence_idx: usize,
+    delta: &[Tuple],
+) -> Result<Vec<Tuple>> {
+    let delta_name = Keyword::from(&format!(
+        "{}$delta",
+        delta_source.to_string_no_prefix()
+    ) as &str);
+
+    let mut seen = 0usize;
+    let rewritten_body =
+        rename_occurrence(&rule.body, delta_source, occurrence_idx, &delta_name, &mut seen);
+    let rewritten_rule = Rule {
+        head: rule.head.clone(),
+        body: rewritten_body,
+        vld: rule.vld,
+    };
+
+    let mut delta_store = TempStore::new();
+    delta_store.insert_new(delta.to_vec())?;
+    let delta_arity = prog
+        .get(delta_source)
+        .map(|rs| rs.arity)
+        .unwrap_or(delta.first().map(|t| t.len()).unwrap_or(0));
+
+    let ret_vars: Vec<Keyword> = rule.head.iter().map(|h| h.name.clone()).collect();
+    let mut keyed = keyed_stores(prog, stores);
+    keyed.insert(delta_name, (delta_store, delta_arity));
+
+    let relation = tx.compile_rule_body(&rewritten_rule, &keyed, &ret_vars)?;
+    relation.iter(tx)
+}
+
+/// Evaluate `prog` to a fixpoint, stratum by stratum, and return the final
+/// contents of the entry rule's store (`PROG_ENTRY`).
+pub(crate) fn semi_naive_evaluate(tx: &mut SessionTx, prog: &DatalogProgram) -> Result<TempStore> {
+    let strata = stratify(prog)?;
+
+    // One store per rule, shared across strata since a later stratum may
+    // still join against an earlier stratum's fully-materialized relation.
+    let mut stores: BTreeMap<Keyword, TempStore> = BTreeMap::new();
+
+    for stratum in &strata {
+        // Deltas are local to this stratum: every rule starts a stratum with
+        // its current store's contents as its "round zero" delta, and the
+        // stratum is done once every rule's delta is empty.
+        let mut deltas: BTreeMap<Keyword, Vec<Tuple>> = BTreeMap::new();
+
+        for name in stratum {
+            stores.entry(name.clone()).or_insert_with(TempStore::new);
+            let rule_set = &prog[name];
+
+            let mut round_new = vec![];
+            for rule in &rule_set.rules {
+                let is_recursive = rule.contained_rules().iter().any(|r| stratum.contains(r));
+                if !is_recursive {
+                    // First round: evaluate non-recursive rule bodies fully
+                    // against the current (stable, lower-stratum) stores.
+                    round_new.extend(eval_rule_body(tx, prog, rule, &stores)?);
+                }
+            }
+            let store = stores.get_mut(name).unwrap();
+            let inserted = store.insert_new(round_new)?;
+            deltas.insert(name.clone(), inserted);
+        }
+
+        loop {
+            let mut any_new = false;
+            let mut next_deltas: BTreeMap<Keyword, Vec<Tuple>> = BTreeMap::new();
+
+            for name in stratum {
+                let rule_set = &prog[name];
+                let mut round_new = vec![];
+                for rule in &rule_set.rules {
+                    let recursive_refs: Vec<_> = rule
+                        .contained_rules()
+                        .into_iter()
+                        .filter(|r| stratum.contains(r))
+                        .collect();
+                    if recursive_refs.is_empty() {
+                        continue;
+                    }
+                    // Substitute the delta for exactly one recursive body atom
+                    // occurrence at a time: this is what keeps semi-naive
+                    // evaluation from recomputing the whole join on every
+                    // round. A relation referenced more than once in the same
+                    // body (e.g. a transitive-closure self-join) needs one
+                    // substitution per occurrence, not one per distinct name,
+                    // or the other occurrence's contribution for this round
+                    // is silently skipped.
+                    for delta_source in &recursive_refs {
+                        let delta = deltas.get(delta_source).cloned().unwrap_or_default();
+                        if delta.is_empty() {
+                            continue;
+                        }
+                        let occurrences = count_rule_occurrences(&rule.body, delta_source);
+                        for occurrence_idx in 0..occurrences {
+                            round_new.extend(eval_rule_body_with_delta(
+                                tx,
+                                prog,
+                                rule,
+                                &stores,
+                                delta_source,
+                                occurrence_idx,
+                                &delta,
+                            )?);
+                        }
+                    }
+                }
+                let store = stores.get_mut(name).unwrap();
+                let inserted = store.insert_new(round_new)?;
+                if !inserted.is_empty() {
+                    any_new = true;
+                }
+                next_deltas.insert(name.clone(), inserted);
+            }
+
+            deltas = next_deltas;
+            if !any_new {
+                break;
+            }
+        }
+    }
+
+    stores
+        .remove(&PROG_ENTRY)
+        .ok_or_else(|| anyhow::anyhow!("program has no entry rule '?'"))
+}